@@ -3,6 +3,7 @@ use std::fs::{File, OpenOptions};
 use std::io;
 use std::io::Write;
 use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
 
 pub(crate) fn overwrite<W>(path: &Path, writer_fn: W) -> io::Result<()>
@@ -14,7 +15,7 @@ where
         "invalid file path, does not have a parent directory",
     ))?;
     fs::create_dir_all(directory)?;
-    let tmp_path = make_tmp_path(path)?;
+    let tmp_path = make_sibling_path(path, ".tmp")?;
 
     {
         let mut tmp_file = OpenOptions::new()
@@ -32,21 +33,58 @@ where
     Ok(())
 }
 
+/// Exclusive advisory lock on a file, released when dropped.
+pub(crate) struct FileLock {
+    _file: File,
+}
+
+/// Block until an exclusive lock is held on a lock file next to the given path.
+///
+/// The lock is taken on a separate file because `overwrite` replaces the file at `path`,
+/// and a lock on the replaced file would not be seen by anyone opening the new one.
+/// Every open file description gets its own lock, so this serializes threads as well as
+/// processes, and the kernel releases it if the holder panics or exits.
+pub(crate) fn lock(path: &Path) -> io::Result<FileLock> {
+    let directory = path.parent().ok_or(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "invalid file path, does not have a parent directory",
+    ))?;
+    fs::create_dir_all(directory)?;
+    let lock_file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(0o600)
+        .open(make_sibling_path(path, ".lock")?)?;
+
+    loop {
+        // SAFETY: flock only takes a file descriptor and flags, and the descriptor is valid:
+        // it is owned by `lock_file`, which stays open for as long as the returned guard lives.
+        if unsafe { libc::flock(lock_file.as_raw_fd(), libc::LOCK_EX) } == 0 {
+            return Ok(FileLock { _file: lock_file });
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
 fn fsync_dir(dir: &Path) -> io::Result<()> {
     let f = File::open(dir)?;
     f.sync_all()
 }
 
-fn make_tmp_path(path: &Path) -> io::Result<PathBuf> {
-    let mut tmp_path = PathBuf::from(path);
-    let mut file_name = tmp_path
+fn make_sibling_path(path: &Path, suffix: &str) -> io::Result<PathBuf> {
+    let mut sibling_path = PathBuf::from(path);
+    let mut file_name = sibling_path
         .file_name()
         .ok_or(io::Error::new(
             io::ErrorKind::InvalidInput,
             "Invalid file path, does not end in a file name",
         ))?
         .to_owned();
-    file_name.push(".tmp");
-    tmp_path.set_file_name(file_name);
-    Ok(tmp_path)
+    file_name.push(suffix);
+    sibling_path.set_file_name(file_name);
+    Ok(sibling_path)
 }
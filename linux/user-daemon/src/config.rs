@@ -1,16 +1,24 @@
+use std::collections::HashSet;
 use std::fs::File;
 use std::io;
 use std::path::Path;
 use std::path::PathBuf;
+use std::time::Duration;
 
 use directories::ProjectDirs;
 use directories::UserDirs;
 use serde::{Deserialize, Serialize};
 use serde_json;
+use tracing::warn;
+use u2f_core::AppId;
 
 use crate::atomic_file;
 use crate::secret_store::SecretStoreType;
 
+/// Shortest configurable timeout, a zero timeout would fail every request before
+/// the user has a chance to answer.
+const MIN_TIMEOUT: Duration = Duration::from_secs(1);
+
 pub struct Config {
     data: ConfigFileData,
     dirs: AppDirs,
@@ -41,6 +49,40 @@ impl Config {
     pub fn home_dir(&self) -> &Path {
         &self.dirs.home_dir
     }
+
+    /// Settings for the U2F service, using the library defaults for anything not configured.
+    pub fn u2f_config(&self) -> u2f_core::Config {
+        let defaults = u2f_core::Config::default();
+        u2f_core::Config {
+            user_presence_optional: self.data.user_presence_optional.clone(),
+            wink_timeout: timeout_setting(
+                "wink_timeout_secs",
+                self.data.wink_timeout_secs,
+                defaults.wink_timeout,
+            ),
+            approval_timeout: timeout_setting(
+                "approval_timeout_secs",
+                self.data.approval_timeout_secs,
+                defaults.approval_timeout,
+            ),
+        }
+    }
+}
+
+fn timeout_setting(name: &str, secs: Option<u64>, default: Duration) -> Duration {
+    match secs.map(Duration::from_secs) {
+        Some(timeout) if timeout < MIN_TIMEOUT => {
+            warn!(
+                setting = name,
+                ?timeout,
+                minimum = ?MIN_TIMEOUT,
+                "Timeout too short, using minimum"
+            );
+            MIN_TIMEOUT
+        }
+        Some(timeout) => timeout,
+        None => default,
+    }
 }
 
 struct AppDirs {
@@ -72,6 +114,16 @@ impl AppDirs {
 #[derive(Default, Serialize, Deserialize)]
 struct ConfigFileData {
     secret_store_type: SecretStoreType,
+
+    /// Base64 encoded application parameters allowed to skip the test of user presence
+    #[serde(default, skip_serializing_if = "HashSet::is_empty")]
+    user_presence_optional: HashSet<AppId>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    wink_timeout_secs: Option<u64>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    approval_timeout_secs: Option<u64>,
 }
 
 struct ConfigFile {
@@ -145,4 +197,63 @@ mod tests {
 
         assert!(ConfigFile::read(&file_path).unwrap().is_none());
     }
+
+    fn config_from_file_contents(contents: &str) -> Config {
+        let temp_dir = TempDir::new("config_tests").unwrap();
+        let dirs = AppDirs {
+            config_dir: temp_dir.path().to_owned(),
+            data_local_dir: PathBuf::new(),
+            home_dir: PathBuf::new(),
+        };
+        std::fs::write(ConfigFile::path(&dirs), contents).unwrap();
+        Config::load_from_dirs(dirs).unwrap()
+    }
+
+    #[test]
+    fn u2f_config_defaults_when_not_configured() {
+        let config = config_from_file_contents(r#"{"secret_store_type": "File"}"#);
+
+        let u2f_config = config.u2f_config();
+        let defaults = u2f_core::Config::default();
+
+        assert!(u2f_config.user_presence_optional.is_empty());
+        assert_eq!(u2f_config.wink_timeout, defaults.wink_timeout);
+        assert_eq!(u2f_config.approval_timeout, defaults.approval_timeout);
+    }
+
+    #[test]
+    fn u2f_config_reads_configured_settings() {
+        let config = config_from_file_contents(
+            r#"{
+                "secret_store_type": "File",
+                "user_presence_optional": ["AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE="],
+                "wink_timeout_secs": 1,
+                "approval_timeout_secs": 60
+            }"#,
+        );
+
+        let u2f_config = config.u2f_config();
+
+        assert!(u2f_config
+            .user_presence_optional
+            .contains(&AppId::from_bytes(&[1u8; 32])));
+        assert_eq!(u2f_config.wink_timeout, Duration::from_secs(1));
+        assert_eq!(u2f_config.approval_timeout, Duration::from_secs(60));
+    }
+
+    #[test]
+    fn u2f_config_raises_zero_timeouts_to_minimum() {
+        let config = config_from_file_contents(
+            r#"{
+                "secret_store_type": "File",
+                "wink_timeout_secs": 0,
+                "approval_timeout_secs": 0
+            }"#,
+        );
+
+        let u2f_config = config.u2f_config();
+
+        assert_eq!(u2f_config.wink_timeout, MIN_TIMEOUT);
+        assert_eq!(u2f_config.approval_timeout, MIN_TIMEOUT);
+    }
 }
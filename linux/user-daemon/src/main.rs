@@ -7,6 +7,7 @@ extern crate dirs;
 extern crate futures;
 extern crate futures_cpupool;
 extern crate lazy_static;
+extern crate libc;
 extern crate notify_rust;
 extern crate pin_project;
 extern crate secret_service;
@@ -113,12 +114,13 @@ async fn main() {
 
 async fn run(socket_path: &Path) -> Result<(), Error> {
     let config = config::Config::load()?;
-    let user_presence = NotificationUserPresence::new();
+    let u2f_config = config.u2f_config();
+    let user_presence = NotificationUserPresence::new(u2f_config.approval_timeout);
     let attestation = u2f_core::self_signed_attestation();
     let crypto = OpenSSLCryptoOperations::new(attestation);
     let secrets = secret_store::build(&config)?;
 
-    let u2f_service = U2fService::new(secrets, crypto, user_presence);
+    let u2f_service = U2fService::with_config(secrets, crypto, user_presence, u2f_config);
 
     let stream = UnixStream::connect(socket_path)
         .await
@@ -39,13 +39,24 @@ pub struct FileStoreV2 {
 impl FileStoreV2 {
     pub fn new(dir: &Path) -> io::Result<FileStoreV2> {
         let path = dir.to_owned().join("secrets.json");
-        Ok(FileStoreV2 { path })
+        Ok(FileStoreV2::with_path(path))
+    }
+
+    fn with_path(path: PathBuf) -> FileStoreV2 {
+        FileStoreV2 { path }
     }
 
     pub fn path(&self) -> &Path {
         &self.path
     }
 
+    /// Held for the duration of every read-modify-write of the file. Otherwise another
+    /// process using the same file, such as a second daemon instance, can interleave
+    /// its update and one of them is lost, e.g. two authentications reusing a counter value.
+    fn lock(&self) -> io::Result<atomic_file::FileLock> {
+        atomic_file::lock(&self.path)
+    }
+
     fn read(&self) -> io::Result<Data> {
         match File::open(&self.path) {
             Ok(file) => serde_json::from_reader(file).map_err(|e| e.into()),
@@ -65,6 +76,7 @@ impl FileStoreV2 {
 
 impl MutableSecretStore for FileStoreV2 {
     fn add_secret(&self, secret: Secret) -> io::Result<()> {
+        let _lock = self.lock()?;
         let mut data = self.read()?;
         data.push(secret);
         self.write(&data)
@@ -73,6 +85,7 @@ impl MutableSecretStore for FileStoreV2 {
 
 impl SecretStore for FileStoreV2 {
     fn add_application_key(&self, key: &ApplicationKey) -> io::Result<()> {
+        let _lock = self.lock()?;
         let mut data = self.read()?;
         data.push(Secret {
             application_key: key.clone(),
@@ -86,6 +99,7 @@ impl SecretStore for FileStoreV2 {
         application: &AppId,
         handle: &KeyHandle,
     ) -> io::Result<Counter> {
+        let _lock = self.lock()?;
         let mut data = self.read()?;
         let secret = data
             .find_secret_mut(application, handle)
@@ -112,6 +126,10 @@ impl SecretStore for FileStoreV2 {
 mod tests {
     extern crate tempdir;
 
+    use std::collections::HashSet;
+    use std::sync::Arc;
+    use std::thread;
+
     use u2f_core::PrivateKey;
 
     use super::*;
@@ -140,7 +158,7 @@ i2L2wGDHkWWIJJSthmgwkZovXHyMXMpDhw==
     fn get_and_increment_counter() {
         let dir = TempDir::new("file_store_tests").unwrap();
         let path = dir.path().join("store");
-        let store = FileStoreV2 { path };
+        let store = FileStoreV2::with_path(path);
         let app_id = fake_app_id();
         let handle = fake_key_handle();
         let key = fake_key();
@@ -157,11 +175,86 @@ i2L2wGDHkWWIJJSthmgwkZovXHyMXMpDhw==
         assert_eq!(counter0 + 1, counter1);
     }
 
+    #[test]
+    fn get_and_increment_counter_continues_from_imported_counter() {
+        let dir = TempDir::new("file_store_tests").unwrap();
+        let path = dir.path().join("store");
+        let store = FileStoreV2::with_path(path);
+        let app_id = fake_app_id();
+        let app_key = ApplicationKey::new(app_id, fake_key_handle(), fake_key());
+        store
+            .add_secret(Secret {
+                application_key: app_key.clone(),
+                counter: 500,
+            })
+            .unwrap();
+
+        let counter = store
+            .get_and_increment_counter(&app_id, &app_key.handle)
+            .unwrap();
+
+        assert_eq!(counter, 501);
+    }
+
+    #[test]
+    fn get_and_increment_counter_concurrently() {
+        let dir = TempDir::new("file_store_tests").unwrap();
+        let path = dir.path().join("store");
+        let store = Arc::new(FileStoreV2::with_path(path));
+        let app_id = fake_app_id();
+        let app_key = ApplicationKey::new(app_id, fake_key_handle(), fake_key());
+        store.add_application_key(&app_key).unwrap();
+
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let store = Arc::clone(&store);
+                let handle = app_key.handle.clone();
+                thread::spawn(move || {
+                    (0..10)
+                        .map(|_| store.get_and_increment_counter(&app_id, &handle).unwrap())
+                        .collect::<Vec<Counter>>()
+                })
+            })
+            .collect();
+        let counters: Vec<Counter> = threads
+            .into_iter()
+            .flat_map(|thread| thread.join().unwrap())
+            .collect();
+
+        let unique_counters: HashSet<Counter> = counters.iter().cloned().collect();
+        assert_eq!(unique_counters.len(), 80);
+        assert_eq!(counters.iter().max(), Some(&80));
+    }
+
+    #[test]
+    fn get_and_increment_counter_after_lock_holder_panicked() {
+        let dir = TempDir::new("file_store_tests").unwrap();
+        let path = dir.path().join("store");
+        let store = Arc::new(FileStoreV2::with_path(path));
+        let app_id = fake_app_id();
+        let app_key = ApplicationKey::new(app_id, fake_key_handle(), fake_key());
+        store.add_application_key(&app_key).unwrap();
+
+        let holder = Arc::clone(&store);
+        let result = thread::spawn(move || {
+            let _lock = holder.lock().unwrap();
+            panic!("panic while holding the lock");
+        })
+        .join();
+        assert!(result.is_err());
+
+        let counter = store
+            .get_and_increment_counter(&app_id, &app_key.handle)
+            .unwrap();
+
+        assert_eq!(counter, 1);
+    }
+
     #[test]
     fn retrieve_application_key() {
         let dir = TempDir::new("file_store_tests").unwrap();
         let path = dir.path().join("store");
-        let store = FileStoreV2 { path };
+        let store = FileStoreV2::with_path(path);
         let app_id = fake_app_id();
         let handle = fake_key_handle();
         let key = fake_key();
@@ -182,7 +275,7 @@ i2L2wGDHkWWIJJSthmgwkZovXHyMXMpDhw==
     fn retrieve_nonexistent_key_is_none() {
         let dir = TempDir::new("file_store_tests").unwrap();
         let path = dir.path().join("store");
-        let store = FileStoreV2 { path };
+        let store = FileStoreV2::with_path(path);
 
         let key = store
             .retrieve_application_key(&fake_app_id(), &fake_key_handle())
@@ -1,10 +1,12 @@
 use std::collections::HashMap;
 use std::io;
+use std::time::Duration;
 
 use async_trait::async_trait;
 use lazy_static::lazy_static;
 use notify_rust::Timeout;
-use notify_rust::{self, Hint, Notification, Urgency};
+use notify_rust::{self, ActionResponse, Hint, Notification, NotificationHandle, Urgency};
+use tokio::runtime;
 use tracing::debug;
 use u2f_core::{try_reverse_app_id, AppId, UserPresence};
 
@@ -16,7 +18,7 @@ const SUMMARY: &str = "Security Key Request";
 const URGENCY: Urgency = Urgency::Critical;
 
 lazy_static! {
-    static ref TIMEOUT: Timeout = Timeout::Milliseconds(10_000);
+    static ref WINK_TIMEOUT: Timeout = Timeout::Milliseconds(10_000);
     static ref WORKAROUND_SERVERS: HashMap<&'static str, &'static str> = {
         let mut ws = HashMap::new();
         // See https://github.com/danstiner/softu2f-linux/issues/12
@@ -26,16 +28,63 @@ lazy_static! {
     };
 }
 
-pub struct NotificationUserPresence;
+pub struct NotificationUserPresence {
+    timeout: Timeout,
+}
 
 impl NotificationUserPresence {
-    pub fn new() -> Self {
-        NotificationUserPresence
+    /// Approval notifications are shown for `timeout`, which should match how long
+    /// the U2F service waits for an answer. Notification servers treat a zero timeout
+    /// as "never expire", so it is rounded up to one millisecond.
+    pub fn new(timeout: Duration) -> Self {
+        let millis = timeout.as_millis().clamp(1, u32::MAX as u128) as u32;
+        NotificationUserPresence {
+            timeout: Timeout::Milliseconds(millis),
+        }
     }
 
     async fn test_user_presence(&self, message: String) -> Result<bool, io::Error> {
         debug!(%message, "test_user_presence");
 
+        let timeout = self.timeout;
+        // Showing and waiting on notifications blocks on D-Bus, run it off the async executor
+        // so the service's approval timeout can still fire while the notification is open.
+        let (handle, default_means_user_present) =
+            tokio::task::spawn_blocking(move || Self::show(message, timeout))
+                .await
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        let id = handle.id();
+        let notification = CloseOnDrop(Some(handle));
+
+        let action = tokio::task::spawn_blocking(move || {
+            let mut action = String::new();
+            notify_rust::handle_action(id, |response: &ActionResponse| {
+                action = match response {
+                    ActionResponse::Custom(action) => action.to_string(),
+                    ActionResponse::Closed(_reason) => NOTIFICATION_CLOSE_ACTION.to_string(),
+                }
+            });
+            action
+        })
+        .await
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        notification.forget();
+
+        let user_present = match action.as_str() {
+            "approve" => true,
+            "deny" => false,
+            "default" => default_means_user_present,
+            NOTIFICATION_CLOSE_ACTION => false,
+            _ => unreachable!("Unknown action taken on notification"),
+        };
+
+        debug!(%action, user_present, "test_user_presence");
+
+        Ok(user_present)
+    }
+
+    /// Returns the shown notification and whether its default action means the user is present.
+    fn show(message: String, timeout: Timeout) -> (NotificationHandle, bool) {
         let mut notification = Notification::new();
         notification
             .appname(APPNAME)
@@ -46,7 +95,7 @@ impl NotificationUserPresence {
             .hint(Hint::Transient(true))
             .hint(Hint::Urgency(URGENCY))
             .urgency(URGENCY)
-            .timeout(*TIMEOUT);
+            .timeout(timeout);
 
         let mut apply_workaround = false;
         let server_info = notify_rust::get_server_information().unwrap();
@@ -69,22 +118,31 @@ impl NotificationUserPresence {
             notification.action("deny", "Deny");
         }
 
-        let notify_handle = notification.show().unwrap();
-
-        let mut action = String::new();
-        notify_handle.wait_for_action(|a| action = a.to_owned());
+        (notification.show().unwrap(), default_means_user_present)
+    }
+}
 
-        let user_present = match action.as_str() {
-            "approve" => true,
-            "deny" => false,
-            "default" => default_means_user_present,
-            NOTIFICATION_CLOSE_ACTION => false,
-            _ => unreachable!("Unknown action taken on notification"),
-        };
+/// Closes a notification that is still waiting for an answer when the approval is abandoned,
+/// e.g. because the service's approval timeout fired. Critical notifications may never expire
+/// on their own, and closing it also ends the blocked wait for its action.
+struct CloseOnDrop(Option<NotificationHandle>);
 
-        debug!(%action, user_present, "test_user_presence");
+impl CloseOnDrop {
+    /// The notification was acted on or closed, there is nothing left to withdraw.
+    fn forget(mut self) {
+        self.0 = None;
+    }
+}
 
-        Ok(user_present)
+impl Drop for CloseOnDrop {
+    fn drop(&mut self) {
+        if let Some(handle) = self.0.take() {
+            debug!(id = handle.id(), "Closing abandoned notification");
+            match runtime::Handle::try_current() {
+                Ok(runtime) => drop(runtime.spawn_blocking(move || handle.close())),
+                Err(_) => handle.close(),
+            }
+        }
     }
 }
 
@@ -113,7 +171,7 @@ impl UserPresence for NotificationUserPresence {
             .hint(Hint::Transient(true))
             .hint(Hint::Urgency(URGENCY))
             .urgency(URGENCY)
-            .timeout(*TIMEOUT);
+            .timeout(*WINK_TIMEOUT);
         Ok(())
     }
 }
@@ -3,16 +3,20 @@ use std::result::Result;
 use crate::serde_base64::{from_base64, to_base64};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use subtle::ConstantTimeEq;
+use thiserror::Error;
 
 #[derive(Copy, Clone, Eq, Hash, PartialEq)]
 pub struct AppId(pub(crate) [u8; 32]);
 
+#[derive(Debug, Error)]
+#[error("Invalid app id length, expected 32 bytes but got {0}")]
+pub struct InvalidAppIdLength(usize);
+
 impl AppId {
+    /// Panics if the slice is not exactly 32 bytes long,
+    /// see `AppId::try_from` for a fallible conversion.
     pub fn from_bytes(slice: &[u8]) -> AppId {
-        assert_eq!(slice.len(), 32);
-        let mut bytes = [0u8; 32];
-        bytes.copy_from_slice(slice);
-        AppId(bytes)
+        AppId::try_from(slice).unwrap()
     }
 
     pub fn eq_consttime(&self, other: &AppId) -> bool {
@@ -24,6 +28,15 @@ impl AppId {
     }
 }
 
+impl TryFrom<&[u8]> for AppId {
+    type Error = InvalidAppIdLength;
+
+    fn try_from(slice: &[u8]) -> Result<AppId, InvalidAppIdLength> {
+        let bytes = <[u8; 32]>::try_from(slice).map_err(|_| InvalidAppIdLength(slice.len()))?;
+        Ok(AppId(bytes))
+    }
+}
+
 impl AsRef<[u8]> for AppId {
     fn as_ref(&self) -> &[u8] {
         self.0.as_ref()
@@ -44,9 +57,9 @@ impl<'de> Deserialize<'de> for AppId {
     where
         D: Deserializer<'de>,
     {
-        let mut bytes = [0u8; 32];
-        bytes.copy_from_slice(&from_base64(deserializer)?);
-        Ok(AppId(bytes))
+        use serde::de::Error;
+        AppId::try_from(from_base64(deserializer)?.as_slice())
+            .map_err(|err| Error::custom(err.to_string()))
     }
 }
 
@@ -57,3 +70,30 @@ impl std::fmt::Debug for AppId {
             .finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+
+    use super::*;
+
+    #[test]
+    fn try_from_with_32_bytes_succeeds() {
+        let app_id = AppId::try_from(&[7u8; 32][..]).unwrap();
+
+        assert_eq!(app_id.as_ref(), &[7u8; 32]);
+    }
+
+    #[test]
+    fn try_from_with_31_bytes_errors() {
+        assert_matches!(AppId::try_from(&[7u8; 31][..]), Err(InvalidAppIdLength(31)));
+    }
+
+    #[test]
+    fn deserialize_with_wrong_length_errors() {
+        let json = format!("\"{}\"", STANDARD.encode([7u8; 31]));
+
+        assert!(serde_json::from_str::<AppId>(&json).is_err());
+    }
+}
@@ -3,6 +3,7 @@ use serde_derive::{Deserialize, Serialize};
 use crate::app_id::AppId;
 use crate::key_handle::KeyHandle;
 use crate::private_key::PrivateKey;
+use crate::public_key::PublicKey;
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct ApplicationKey {
@@ -22,4 +23,7 @@ impl ApplicationKey {
     pub(crate) fn key(&self) -> &PrivateKey {
         &self.key
     }
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey::from_key(&self.key)
+    }
 }
@@ -0,0 +1,34 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
+use crate::app_id::AppId;
+
+/// Policy knobs for a [`U2fService`](crate::U2fService).
+///
+/// The default configuration enforces a test of user presence for every
+/// registration and authentication, matching the behavior of a hardware key.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Applications that may authenticate without a test of user presence
+    /// when they send the "dont-enforce-user-presence-and-sign" control byte.
+    /// Requests from any other application still require user presence.
+    pub user_presence_optional: HashSet<AppId>,
+
+    /// Upper bound on how long a wink request waits for the user presence
+    /// implementation to finish its indication, so the response is not delayed.
+    pub wink_timeout: Duration,
+
+    /// How long to wait for the user to approve a registration or authentication
+    /// before giving up and reporting that the test of user presence was not satisfied.
+    pub approval_timeout: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            user_presence_optional: HashSet::new(),
+            wink_timeout: Duration::from_secs(3),
+            approval_timeout: Duration::from_secs(30),
+        }
+    }
+}
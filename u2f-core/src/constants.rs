@@ -20,5 +20,4 @@ pub(crate) const AUTH_DONT_ENFORCE: u8 = 0x08; // Don't enforce user presence an
 pub(crate) const DEFAULT_KEY_HANDLE_LEN: usize = 64;
 pub(crate) const MAX_KEY_HANDLE_LEN: usize = 255;
 
-#[cfg(test)]
 pub(crate) const EC_POINT_FORMAT_UNCOMPRESSED: u8 = 0x04;
@@ -1,14 +1,12 @@
 use std::fmt::{self, Debug};
 use std::result::Result;
 
-use crate::constants::{DEFAULT_KEY_HANDLE_LEN, MAX_KEY_HANDLE_LEN};
+use crate::constants::MAX_KEY_HANDLE_LEN;
 use crate::serde_base64::{from_base64, to_base64};
-use rand::distributions::{Distribution, Standard};
-use rand::Rng;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use subtle::ConstantTimeEq;
 
-#[derive(Clone, Eq, PartialEq)]
+#[derive(Clone, Eq)]
 pub struct KeyHandle(Vec<u8>);
 
 impl KeyHandle {
@@ -26,20 +24,17 @@ impl KeyHandle {
     }
 }
 
-impl AsRef<[u8]> for KeyHandle {
-    fn as_ref(&self) -> &[u8] {
-        self.0.as_ref()
+/// Key handles are random identifiers used to look up keys in the secret store. Comparing them
+/// in constant time avoids leaking through timing which key handles are registered.
+impl PartialEq for KeyHandle {
+    fn eq(&self, other: &KeyHandle) -> bool {
+        self.eq_consttime(other)
     }
 }
 
-impl Distribution<KeyHandle> for Standard {
-    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> KeyHandle {
-        KeyHandle(
-            Standard
-                .sample_iter(rng)
-                .take(DEFAULT_KEY_HANDLE_LEN)
-                .collect(),
-        )
+impl AsRef<[u8]> for KeyHandle {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_ref()
     }
 }
 
@@ -66,3 +61,30 @@ impl<'de> Deserialize<'de> for KeyHandle {
         Ok(KeyHandle(from_base64(deserializer)?))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn eq_same_bytes() {
+        let handle = KeyHandle::from(&[7u8; 64]);
+        assert_eq!(handle, KeyHandle::from(&[7u8; 64]));
+        assert!(handle.eq_consttime(&KeyHandle::from(&[7u8; 64])));
+    }
+
+    #[test]
+    fn eq_differs_at_any_position() {
+        let handle = KeyHandle::from(&[7u8; 64]);
+        for position in [0, 31, 63] {
+            let mut bytes = [7u8; 64];
+            bytes[position] = 8;
+            assert_ne!(handle, KeyHandle::from(&bytes));
+        }
+    }
+
+    #[test]
+    fn eq_different_lengths() {
+        assert_ne!(KeyHandle::from(&[7u8; 64]), KeyHandle::from(&[7u8; 32]));
+    }
+}
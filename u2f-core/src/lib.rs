@@ -14,6 +14,7 @@ extern crate rand;
 extern crate ring;
 extern crate serde;
 extern crate serde_derive;
+extern crate serde_json;
 extern crate subtle;
 extern crate tokio;
 extern crate tower;
@@ -33,23 +34,25 @@ use thiserror::Error;
 pub use tower::Service;
 use tracing::{debug, error, info, trace};
 
-pub use crate::app_id::AppId;
+pub use crate::app_id::{AppId, InvalidAppIdLength};
 pub use crate::application_key::ApplicationKey;
 use crate::attestation::AttestationCertificate;
+pub use crate::config::Config;
 use crate::constants::*;
 pub use crate::key_handle::KeyHandle;
 pub use crate::known_app_ids::try_reverse_app_id;
 use crate::known_app_ids::{BOGUS_APP_ID_HASH_CHROME, BOGUS_APP_ID_HASH_FIREFOX};
 pub use crate::openssl_crypto::OpenSSLCryptoOperations;
 pub use crate::private_key::PrivateKey;
-use crate::public_key::PublicKey;
-pub use crate::request::{AuthenticateControlCode, Request};
+pub use crate::public_key::PublicKey;
+pub use crate::request::{AuthenticateControlCode, Request, RequestDecodeError};
 pub use crate::response::Response;
 pub use crate::self_signed_attestation::self_signed_attestation;
 
 mod app_id;
 mod application_key;
 mod attestation;
+mod config;
 mod constants;
 mod key_handle;
 mod known_app_ids;
@@ -88,7 +91,10 @@ impl StatusCode {
 }
 
 #[derive(Debug, Error)]
-pub enum SignError {}
+pub enum SignError {
+    #[error("OpenSSL error: {0}")]
+    OpenSsl(#[from] openssl::error::ErrorStack),
+}
 
 pub type Counter = u32;
 
@@ -103,11 +109,22 @@ impl AsRef<[u8]> for Challenge {
 
 pub trait Signature: AsRef<[u8]> + Debug + Send {}
 
+/// Asks the user to approve requests, e.g. by showing a prompt.
+///
+/// The service stops waiting for an approval once its approval timeout fires and
+/// drops the pending future. Implementations must withdraw their prompt when that
+/// happens: clients retry a request that timed out, and a prompt left open would
+/// be joined by a new one on every retry while answering it does nothing.
 #[async_trait]
 pub trait UserPresence {
     async fn approve_registration(&self, application: &AppId) -> Result<bool, io::Error>;
     async fn approve_authentication(&self, application: &AppId) -> Result<bool, io::Error>;
     async fn wink(&self) -> Result<(), io::Error>;
+
+    /// Called after each successful authentication, including those that skipped the test of
+    /// user presence because the application is in `Config::user_presence_optional`.
+    /// Does nothing by default, implementations must not block as the response is waiting.
+    fn authenticated(&self, _application: &AppId, _user_present: bool) {}
 }
 
 pub trait CryptoOperations {
@@ -173,6 +190,9 @@ pub enum AuthenticateError {
     #[error("Approval denied")]
     ApprovalDenied,
 
+    #[error("Approval timed out")]
+    ApprovalTimedOut,
+
     #[error("Invalid key handle")]
     InvalidKeyHandle,
 
@@ -188,6 +208,9 @@ pub enum RegisterError {
     #[error("Approval denied")]
     ApprovalDenied,
 
+    #[error("Approval timed out")]
+    ApprovalTimedOut,
+
     #[error("I/O error: {0}")]
     Io(#[from] io::Error),
 
@@ -212,6 +235,15 @@ where
     pub fn new(secrets: Secrets, crypto: Crypto, presence: Presence) -> Self {
         Self(Rc::new(U2f::new(secrets, crypto, presence)))
     }
+
+    pub fn with_config(
+        secrets: Secrets,
+        crypto: Crypto,
+        presence: Presence,
+        config: Config,
+    ) -> Self {
+        Self(Rc::new(U2f::with_config(secrets, crypto, presence, config)))
+    }
 }
 
 impl<Secrets, Crypto, Presence> Service<Request> for U2fService<Secrets, Crypto, Presence>
@@ -267,6 +299,7 @@ struct U2f<Secrets, Crypto, Presence> {
     secrets: Secrets,
     crypto: Crypto,
     presence: Presence,
+    config: Config,
 }
 
 impl<Secrets, Crypto, Presence> U2f<Secrets, Crypto, Presence>
@@ -276,10 +309,20 @@ where
     Presence: UserPresence,
 {
     pub fn new(secrets: Secrets, crypto: Crypto, presence: Presence) -> Self {
+        Self::with_config(secrets, crypto, presence, Config::default())
+    }
+
+    pub fn with_config(
+        secrets: Secrets,
+        crypto: Crypto,
+        presence: Presence,
+        config: Config,
+    ) -> Self {
         U2f {
             secrets,
             crypto,
             presence,
+            config,
         }
     }
 
@@ -298,7 +341,13 @@ where
 
         match control_code {
             AuthenticateControlCode::CheckOnly => {
-                let is_valid = self.is_valid_key_handle(&key_handle, &application)?;
+                let is_valid = match self.is_valid_key_handle(&key_handle, &application) {
+                    Ok(is_valid) => is_valid,
+                    Err(err) => {
+                        error!(error = ?err, "I/O error");
+                        return Ok(Response::UnknownError);
+                    }
+                };
                 debug!(is_valid_key_handle = is_valid, "ControlCode::CheckOnly");
                 if is_valid {
                     info!("Valid key handle");
@@ -307,40 +356,20 @@ where
                     Ok(Response::InvalidKeyHandle)
                 }
             }
-            AuthenticateControlCode::EnforceUserPresenceAndSign => {
-                match self.authenticate(application, challenge, key_handle).await {
-                    Ok(authentication) => {
-                        info!(user_present = authentication.user_present, "Authenticated");
-                        Ok(Response::Authentication {
-                            counter: authentication.counter,
-                            signature: authentication.signature,
-                            user_present: authentication.user_present,
-                        })
-                    }
-                    Err(err) => match err {
-                        AuthenticateError::ApprovalDenied => {
-                            info!("Authentication was not approved by user");
-                            Ok(Response::ApprovalDenied)
-                        }
-                        AuthenticateError::InvalidKeyHandle => {
-                            info!("InvalidKeyHandle");
-                            Ok(Response::InvalidKeyHandle)
-                        }
-                        AuthenticateError::Io(err) => {
-                            error!(error = ?err, "I/O error");
-                            Ok(Response::UnknownError)
-                        }
-                        AuthenticateError::Signing(err) => {
-                            error!(error = ?err, "Signing error");
-                            Ok(Response::UnknownError)
-                        }
-                    },
-                }
-            }
+            AuthenticateControlCode::EnforceUserPresenceAndSign => Ok(authentication_response(
+                self.authenticate(application, challenge, key_handle, true)
+                    .await,
+            )),
             AuthenticateControlCode::DontEnforceUserPresenceAndSign => {
-                info!("DontEnforceUserPresenceAndSign");
-                // TODO Implement
-                Ok(Response::TestOfUserPresenceNotSatisfied)
+                // Skipping the test of user presence is an explicit opt-in per application,
+                // all other applications are treated as if presence was enforced.
+                let user_presence_optional =
+                    self.config.user_presence_optional.contains(&application);
+                info!(user_presence_optional, "DontEnforceUserPresenceAndSign");
+                Ok(authentication_response(
+                    self.authenticate(application, challenge, key_handle, !user_presence_optional)
+                        .await,
+                ))
             }
         }
     }
@@ -382,9 +411,17 @@ where
                     info!("Registration was not approved by user");
                     Ok(Response::ApprovalDenied)
                 }
-                RegisterError::Io(err) => Err(err),
-                RegisterError::Signing(_) => {
-                    Err(io::Error::new(io::ErrorKind::Other, "Signing error"))
+                RegisterError::ApprovalTimedOut => {
+                    info!("Registration was not approved by user in time");
+                    Ok(Response::TestOfUserPresenceNotSatisfied)
+                }
+                RegisterError::Io(err) => {
+                    error!(error = ?err, "I/O error");
+                    Ok(Response::UnknownError)
+                }
+                RegisterError::Signing(err) => {
+                    error!(error = ?err, "Signing error");
+                    Ok(Response::UnknownError)
                 }
             },
         }
@@ -393,45 +430,79 @@ where
     async fn wink_request(&self) -> Result<Response, io::Error> {
         debug!("Wink");
 
-        self.presence
-            .wink()
-            .await
-            .map(|_| Response::DidWink)
-            .or_else(move |err| {
+        match tokio::time::timeout(self.config.wink_timeout, self.presence.wink()).await {
+            Ok(Ok(())) => Ok(Response::DidWink),
+            Ok(Err(err)) => {
                 error!(error = ?err, "I/O error");
                 Ok(Response::UnknownError)
-            })
+            }
+            Err(_) => {
+                // Winking is only a visual aid, respond rather than hold up the transaction
+                debug!(timeout = ?self.config.wink_timeout, "Wink timed out");
+                Ok(Response::DidWink)
+            }
+        }
     }
 
     fn is_valid_key_handle(&self, key_handle: &KeyHandle, application: &AppId) -> io::Result<bool> {
         debug!("is_valid_key_handle");
-        self.secrets
-            .retrieve_application_key(application, key_handle)
+        self.retrieve_application_key(application, key_handle)
             .map(|key| key.is_some())
     }
 
+    /// Retrieve the key for a key handle, only if it was registered by the given application.
+    ///
+    /// A key handle is only meaningful in combination with the application that registered it,
+    /// so do not rely on the secret store alone to enforce this. Signing with a key registered
+    /// by another application would let one site make use of another site's registration.
+    fn retrieve_application_key(
+        &self,
+        application: &AppId,
+        key_handle: &KeyHandle,
+    ) -> io::Result<Option<ApplicationKey>> {
+        let key = self
+            .secrets
+            .retrieve_application_key(application, key_handle)?;
+        match key {
+            Some(key) if !key.application.eq_consttime(application) => {
+                error!(
+                    requested = ?application,
+                    registered = ?key.application,
+                    "Secret store returned a key registered by a different application"
+                );
+                Ok(None)
+            }
+            key => Ok(key),
+        }
+    }
+
     async fn authenticate(
         &self,
         application: AppId,
         challenge: Challenge,
         key_handle: KeyHandle,
+        enforce_user_presence: bool,
     ) -> Result<Authentication, AuthenticateError> {
-        debug!(appid = ?application, "authenticate");
+        debug!(appid = ?application, enforce_user_presence, "authenticate");
 
         let application_key = self
-            .secrets
             .retrieve_application_key(&application, &key_handle)?
             .ok_or(AuthenticateError::InvalidKeyHandle)?;
 
-        if !self
-            .presence
-            .approve_authentication(&application_key.application)
-            .await?
-        {
-            return Err(AuthenticateError::ApprovalDenied);
-        }
-
-        let user_present = true;
+        let user_present = if enforce_user_presence {
+            let approval = self
+                .presence
+                .approve_authentication(&application_key.application);
+            if !tokio::time::timeout(self.config.approval_timeout, approval)
+                .await
+                .map_err(|_| AuthenticateError::ApprovalTimedOut)??
+            {
+                return Err(AuthenticateError::ApprovalDenied);
+            }
+            true
+        } else {
+            false
+        };
 
         let counter = self
             .secrets
@@ -439,15 +510,17 @@ where
 
         let user_presence_byte = user_presence_byte(user_present);
 
-        let signature = self.crypto.sign(
-            application_key.key(),
-            &message_to_sign_for_authenticate(
-                &application_key.application,
-                &challenge,
-                user_presence_byte,
-                counter,
-            ),
-        )?;
+        let message = message_to_sign_for_authenticate(
+            &application_key.application,
+            &challenge,
+            user_presence_byte,
+            counter,
+        );
+        trace!(message = %hex::encode(&message), "Signing authentication message");
+        let signature = self.crypto.sign(application_key.key(), &message)?;
+
+        self.presence
+            .authenticated(&application_key.application, user_present);
 
         Ok(Authentication {
             counter,
@@ -463,7 +536,11 @@ where
     ) -> Result<Registration, RegisterError> {
         debug!("register");
 
-        if !self.presence.approve_registration(&application).await? {
+        let approval = self.presence.approve_registration(&application);
+        if !tokio::time::timeout(self.config.approval_timeout, approval)
+            .await
+            .map_err(|_| RegisterError::ApprovalTimedOut)??
+        {
             return Err(RegisterError::ApprovalDenied);
         }
 
@@ -476,12 +553,14 @@ where
 
         let public_key = PublicKey::from_key(application_key.key());
         let public_key_bytes: Vec<u8> = public_key.to_raw();
-        let signature = self.crypto.attest(&message_to_sign_for_register(
+        let message = message_to_sign_for_register(
             &application_key.application,
             &challenge,
             &public_key_bytes,
             &application_key.handle,
-        ))?;
+        );
+        trace!(message = %hex::encode(&message), "Signing registration message");
+        let signature = self.crypto.attest(&message)?;
         let attestation_certificate = self.crypto.get_attestation_certificate();
 
         Ok(Registration {
@@ -493,6 +572,39 @@ where
     }
 }
 
+fn authentication_response(result: Result<Authentication, AuthenticateError>) -> Response {
+    match result {
+        Ok(authentication) => {
+            info!(user_present = authentication.user_present, "Authenticated");
+            Response::Authentication {
+                counter: authentication.counter,
+                signature: authentication.signature,
+                user_present: authentication.user_present,
+            }
+        }
+        Err(AuthenticateError::ApprovalDenied) => {
+            info!("Authentication was not approved by user");
+            Response::ApprovalDenied
+        }
+        Err(AuthenticateError::ApprovalTimedOut) => {
+            info!("Authentication was not approved by user in time");
+            Response::TestOfUserPresenceNotSatisfied
+        }
+        Err(AuthenticateError::InvalidKeyHandle) => {
+            info!("InvalidKeyHandle");
+            Response::InvalidKeyHandle
+        }
+        Err(AuthenticateError::Io(err)) => {
+            error!(error = ?err, "I/O error");
+            Response::UnknownError
+        }
+        Err(AuthenticateError::Signing(err)) => {
+            error!(error = ?err, "Signing error");
+            Response::UnknownError
+        }
+    }
+}
+
 /// User presence byte [1 byte]. Bit 0 indicates whether user presence was verified.
 /// If Bit 0 is is to 1, then user presence was verified. If Bit 0 is set to 0,
 /// then user presence was not verified. The values of Bit 1 through 7 shall be 0;
@@ -557,9 +669,12 @@ fn message_to_sign_for_register(
 #[cfg(test)]
 mod tests {
     use std::collections::HashMap;
-    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
 
     use async_trait::async_trait;
+    use futures::future;
     use openssl::hash::MessageDigest;
     use openssl::pkey::{HasPublic, PKey, PKeyRef};
     use openssl::sign::Verifier;
@@ -606,6 +721,81 @@ mod tests {
         }
     }
 
+    /// Never answers, and counts prompts withdrawn because their approval was dropped.
+    #[derive(Default)]
+    struct NeverApprovingUserPresence {
+        withdrawn_prompts: Arc<AtomicUsize>,
+    }
+
+    struct Prompt(Arc<AtomicUsize>);
+
+    impl Drop for Prompt {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    impl NeverApprovingUserPresence {
+        async fn prompt(&self) -> Result<bool, io::Error> {
+            let _prompt = Prompt(Arc::clone(&self.withdrawn_prompts));
+            future::pending().await
+        }
+    }
+
+    #[async_trait]
+    impl UserPresence for NeverApprovingUserPresence {
+        async fn approve_registration(&self, _: &AppId) -> Result<bool, io::Error> {
+            self.prompt().await
+        }
+        async fn approve_authentication(&self, _: &AppId) -> Result<bool, io::Error> {
+            self.prompt().await
+        }
+        async fn wink(&self) -> Result<(), io::Error> {
+            Ok(())
+        }
+    }
+
+    /// Approves everything and records each reported authentication.
+    #[derive(Default)]
+    struct RecordingUserPresence {
+        authentications: Mutex<Vec<(AppId, bool)>>,
+    }
+
+    #[async_trait]
+    impl UserPresence for RecordingUserPresence {
+        async fn approve_registration(&self, _: &AppId) -> Result<bool, io::Error> {
+            Ok(true)
+        }
+        async fn approve_authentication(&self, _: &AppId) -> Result<bool, io::Error> {
+            Ok(true)
+        }
+        async fn wink(&self) -> Result<(), io::Error> {
+            Ok(())
+        }
+        fn authenticated(&self, application: &AppId, user_present: bool) {
+            self.authentications
+                .lock()
+                .unwrap()
+                .push((*application, user_present));
+        }
+    }
+
+    struct SlowWinkUserPresence;
+
+    #[async_trait]
+    impl UserPresence for SlowWinkUserPresence {
+        async fn approve_registration(&self, _: &AppId) -> Result<bool, io::Error> {
+            Ok(true)
+        }
+        async fn approve_authentication(&self, _: &AppId) -> Result<bool, io::Error> {
+            Ok(true)
+        }
+        async fn wink(&self) -> Result<(), io::Error> {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            Ok(())
+        }
+    }
+
     struct InMemorySecretStore(Mutex<InMemorySecretStoreInner>);
 
     struct InMemorySecretStoreInner {
@@ -664,6 +854,119 @@ mod tests {
         }
     }
 
+    /// Simulates a faulty secret store that finds keys by handle alone,
+    /// ignoring which application the key was registered by.
+    struct IgnoresApplicationSecretStore(ApplicationKey);
+
+    impl SecretStore for IgnoresApplicationSecretStore {
+        fn add_application_key(&self, _key: &ApplicationKey) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn get_and_increment_counter(
+            &self,
+            _application: &AppId,
+            _handle: &KeyHandle,
+        ) -> io::Result<Counter> {
+            Ok(0)
+        }
+
+        fn retrieve_application_key(
+            &self,
+            _application: &AppId,
+            handle: &KeyHandle,
+        ) -> io::Result<Option<ApplicationKey>> {
+            if self.0.handle.eq_consttime(handle) {
+                Ok(Some(self.0.clone()))
+            } else {
+                Ok(None)
+            }
+        }
+    }
+
+    struct FailingRng;
+
+    impl rand::RngCore for FailingRng {
+        fn next_u32(&mut self) -> u32 {
+            panic!("FailingRng only supports try_fill_bytes")
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            panic!("FailingRng only supports try_fill_bytes")
+        }
+
+        fn fill_bytes(&mut self, _dest: &mut [u8]) {
+            panic!("FailingRng only supports try_fill_bytes")
+        }
+
+        fn try_fill_bytes(&mut self, _dest: &mut [u8]) -> Result<(), rand::Error> {
+            Err(rand::Error::new(io::Error::new(
+                io::ErrorKind::Other,
+                "entropy source unavailable",
+            )))
+        }
+    }
+
+    impl rand::CryptoRng for FailingRng {}
+
+    /// Simulates a secret store whose backend cannot be reached.
+    struct UnavailableSecretStore;
+
+    impl UnavailableSecretStore {
+        fn error() -> io::Error {
+            io::Error::new(io::ErrorKind::ConnectionRefused, "secret store unavailable")
+        }
+    }
+
+    impl SecretStore for UnavailableSecretStore {
+        fn add_application_key(&self, _key: &ApplicationKey) -> io::Result<()> {
+            Err(Self::error())
+        }
+
+        fn get_and_increment_counter(
+            &self,
+            _application: &AppId,
+            _handle: &KeyHandle,
+        ) -> io::Result<Counter> {
+            Err(Self::error())
+        }
+
+        fn retrieve_application_key(
+            &self,
+            _application: &AppId,
+            _handle: &KeyHandle,
+        ) -> io::Result<Option<ApplicationKey>> {
+            Err(Self::error())
+        }
+    }
+
+    /// Simulates cryptographic operations whose signing backend fails.
+    struct FailingSignCryptoOperations(OpenSSLCryptoOperations);
+
+    impl FailingSignCryptoOperations {
+        fn error() -> SignError {
+            SignError::OpenSsl(openssl::error::ErrorStack::get())
+        }
+    }
+
+    impl CryptoOperations for FailingSignCryptoOperations {
+        fn attest(&self, _data: &[u8]) -> Result<Box<dyn Signature>, SignError> {
+            Err(Self::error())
+        }
+
+        fn generate_application_key(&self, application: &AppId) -> io::Result<ApplicationKey> {
+            self.0.generate_application_key(application)
+        }
+
+        fn get_attestation_certificate(&self) -> AttestationCertificate {
+            self.0.get_attestation_certificate()
+        }
+
+        fn sign(&self, _key: &PrivateKey, _data: &[u8]) -> Result<Box<dyn Signature>, SignError> {
+            Err(Self::error())
+        }
+    }
+
     fn get_test_attestation() -> Attestation {
         Attestation {
             certificate: AttestationCertificate::from_pem(
@@ -734,7 +1037,8 @@ AwEHoUQDQgAEryDZdIOGjRKLLyG6Mkc4oSVUDBndagZDDbdwLcUdNLzFlHx/yqYl
         let key_handle = fake_key_handle();
 
         assert_matches!(
-            u2f.authenticate(application, challenge, key_handle).await,
+            u2f.authenticate(application, challenge, key_handle, true)
+                .await,
             Err(AuthenticateError::InvalidKeyHandle)
         );
     }
@@ -753,11 +1057,87 @@ AwEHoUQDQgAEryDZdIOGjRKLLyG6Mkc4oSVUDBndagZDDbdwLcUdNLzFlHx/yqYl
             .await
             .unwrap();
 
-        u2f.authenticate(application, challenge, registration.key_handle)
+        u2f.authenticate(application, challenge, registration.key_handle, true)
             .await
             .unwrap();
     }
 
+    #[tokio::test]
+    async fn authenticate_signs_counter_from_secret_store() {
+        let secrets = InMemorySecretStore::new();
+        let crypto = OpenSSLCryptoOperations::new(get_test_attestation());
+        let presence = FakeUserPresence::always_approve();
+        let u2f = U2f::new(secrets, crypto, presence);
+
+        let application = AppId([1u8; 32]);
+        let registration = u2f.register(application, fake_challenge()).await.unwrap();
+        u2f.secrets
+            .0
+            .lock()
+            .unwrap()
+            .counters
+            .insert(application, 41);
+        let user_public_key = PublicKey::from_bytes(&registration.user_public_key).unwrap();
+        let user_public_key = PKey::from_ec_key(user_public_key.into()).unwrap();
+
+        for expected_counter in [41, 42] {
+            let challenge = fake_challenge();
+            let authentication = u2f
+                .authenticate(
+                    application,
+                    challenge.clone(),
+                    registration.key_handle.clone(),
+                    true,
+                )
+                .await
+                .unwrap();
+
+            assert_eq!(authentication.counter, expected_counter);
+            let signed_data = message_to_sign_for_authenticate(
+                &application,
+                &challenge,
+                user_presence_byte(true),
+                expected_counter,
+            );
+            verify_signature(
+                authentication.signature.as_ref(),
+                signed_data.as_ref(),
+                &user_public_key,
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn authenticate_with_handle_registered_by_other_application_errors() {
+        let crypto = OpenSSLCryptoOperations::new(get_test_attestation());
+        let registered_application = AppId([1u8; 32]);
+        let application_key = crypto
+            .generate_application_key(&registered_application)
+            .unwrap();
+        let key_handle = application_key.handle.clone();
+        let secrets = IgnoresApplicationSecretStore(application_key);
+        let presence = FakeUserPresence::always_approve();
+        let u2f = U2f::new(secrets, crypto, presence);
+
+        let application = AppId([2u8; 32]);
+        let challenge = fake_challenge();
+
+        assert_matches!(
+            u2f.is_valid_key_handle(&key_handle, &application),
+            Ok(false)
+        );
+        assert_matches!(
+            u2f.authenticate(application, challenge.clone(), key_handle.clone(), true)
+                .await,
+            Err(AuthenticateError::InvalidKeyHandle)
+        );
+        assert_matches!(
+            u2f.authenticate(registered_application, challenge, key_handle, true)
+                .await,
+            Ok(_)
+        );
+    }
+
     #[tokio::test]
     async fn authenticate_with_rejected_approval_errors() {
         let secrets = InMemorySecretStore::new();
@@ -776,12 +1156,265 @@ AwEHoUQDQgAEryDZdIOGjRKLLyG6Mkc4oSVUDBndagZDDbdwLcUdNLzFlHx/yqYl
             .unwrap();
 
         assert_matches!(
-            u2f.authenticate(application, challenge, registration.key_handle)
+            u2f.authenticate(application, challenge, registration.key_handle, true)
                 .await,
             Err(AuthenticateError::ApprovalDenied)
         );
     }
 
+    #[tokio::test]
+    async fn authenticate_dont_enforce_with_user_presence_optional_skips_approval() {
+        let secrets = InMemorySecretStore::new();
+        let crypto = OpenSSLCryptoOperations::new(get_test_attestation());
+        let presence = FakeUserPresence {
+            should_approve_authentication: false,
+            should_approve_registration: true,
+        };
+        let application = fake_app_id();
+        let config = Config {
+            user_presence_optional: [application].into_iter().collect(),
+            ..Config::default()
+        };
+        let u2f = U2f::with_config(secrets, crypto, presence, config);
+
+        let challenge = fake_challenge();
+        let registration = u2f.register(application, challenge.clone()).await.unwrap();
+
+        let response = u2f
+            .authenticate_request(
+                AuthenticateControlCode::DontEnforceUserPresenceAndSign,
+                challenge,
+                application,
+                registration.key_handle,
+            )
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            response,
+            Response::Authentication {
+                user_present: false,
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn authenticated_is_reported_once_per_successful_authentication() {
+        let secrets = InMemorySecretStore::new();
+        let crypto = OpenSSLCryptoOperations::new(get_test_attestation());
+        let application = AppId([1u8; 32]);
+        let silent_application = AppId([2u8; 32]);
+        let config = Config {
+            user_presence_optional: [silent_application].into_iter().collect(),
+            ..Config::default()
+        };
+        let u2f = U2f::with_config(secrets, crypto, RecordingUserPresence::default(), config);
+        let registration = u2f.register(application, fake_challenge()).await.unwrap();
+        let silent_registration = u2f
+            .register(silent_application, fake_challenge())
+            .await
+            .unwrap();
+
+        let response = u2f
+            .authenticate_request(
+                AuthenticateControlCode::EnforceUserPresenceAndSign,
+                fake_challenge(),
+                application,
+                registration.key_handle,
+            )
+            .await
+            .unwrap();
+        assert!(matches!(response, Response::Authentication { .. }));
+        let response = u2f
+            .authenticate_request(
+                AuthenticateControlCode::DontEnforceUserPresenceAndSign,
+                fake_challenge(),
+                silent_application,
+                silent_registration.key_handle,
+            )
+            .await
+            .unwrap();
+        assert!(matches!(response, Response::Authentication { .. }));
+        let response = u2f
+            .authenticate_request(
+                AuthenticateControlCode::EnforceUserPresenceAndSign,
+                fake_challenge(),
+                application,
+                KeyHandle::from(&[9u8; 8]),
+            )
+            .await
+            .unwrap();
+        assert!(matches!(response, Response::InvalidKeyHandle));
+
+        assert_eq!(
+            *u2f.presence.authentications.lock().unwrap(),
+            vec![(application, true), (silent_application, false)]
+        );
+    }
+
+    #[tokio::test]
+    async fn authenticate_dont_enforce_without_user_presence_optional_requires_approval() {
+        let secrets = InMemorySecretStore::new();
+        let crypto = OpenSSLCryptoOperations::new(get_test_attestation());
+        let presence = FakeUserPresence {
+            should_approve_authentication: false,
+            should_approve_registration: true,
+        };
+        let config = Config {
+            user_presence_optional: [AppId([1u8; 32])].into_iter().collect(),
+            ..Config::default()
+        };
+        let u2f = U2f::with_config(secrets, crypto, presence, config);
+
+        let application = fake_app_id();
+        let challenge = fake_challenge();
+        let registration = u2f.register(application, challenge.clone()).await.unwrap();
+
+        let response = u2f
+            .authenticate_request(
+                AuthenticateControlCode::DontEnforceUserPresenceAndSign,
+                challenge,
+                application,
+                registration.key_handle,
+            )
+            .await
+            .unwrap();
+
+        assert!(matches!(response, Response::ApprovalDenied));
+    }
+
+    #[tokio::test]
+    async fn register_with_failing_rng_errors() {
+        let secrets = InMemorySecretStore::new();
+        let crypto = OpenSSLCryptoOperations::with_rng(get_test_attestation(), FailingRng);
+        let presence = FakeUserPresence::always_approve();
+        let u2f = U2f::new(secrets, crypto, presence);
+
+        let application = fake_app_id();
+        let challenge = fake_challenge();
+
+        let err = match u2f.register(application, challenge).await {
+            Err(RegisterError::Io(err)) => err,
+            other => panic!("expected an I/O error, got {:?}", other.map(|_| ())),
+        };
+        let source = err.get_ref().expect("RNG error is kept as the source");
+        assert!(source.downcast_ref::<rand::Error>().is_some());
+    }
+
+    #[tokio::test]
+    async fn requests_with_unavailable_secret_store_respond_with_unknown_error() {
+        let secrets = UnavailableSecretStore;
+        let crypto = OpenSSLCryptoOperations::new(get_test_attestation());
+        let presence = FakeUserPresence::always_approve();
+        let u2f = U2f::new(secrets, crypto, presence);
+
+        let application = AppId([1u8; 32]);
+
+        let register = u2f
+            .register_request(application, fake_challenge())
+            .await
+            .unwrap();
+        assert!(matches!(register, Response::UnknownError));
+
+        for control_code in [
+            AuthenticateControlCode::CheckOnly,
+            AuthenticateControlCode::EnforceUserPresenceAndSign,
+        ] {
+            let authenticate = u2f
+                .authenticate_request(
+                    control_code,
+                    fake_challenge(),
+                    application,
+                    fake_key_handle(),
+                )
+                .await
+                .unwrap();
+            assert!(matches!(authenticate, Response::UnknownError));
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_with_failing_signing_respond_with_unknown_error() {
+        let application = AppId([1u8; 32]);
+        let secrets = InMemorySecretStore::new();
+        let application_key = OpenSSLCryptoOperations::new(get_test_attestation())
+            .generate_application_key(&application)
+            .unwrap();
+        secrets.add_application_key(&application_key).unwrap();
+        let crypto =
+            FailingSignCryptoOperations(OpenSSLCryptoOperations::new(get_test_attestation()));
+        let presence = FakeUserPresence::always_approve();
+        let u2f = U2f::new(secrets, crypto, presence);
+
+        let authenticate = u2f
+            .authenticate_request(
+                AuthenticateControlCode::EnforceUserPresenceAndSign,
+                fake_challenge(),
+                application,
+                application_key.handle,
+            )
+            .await
+            .unwrap();
+        assert!(matches!(authenticate, Response::UnknownError));
+
+        let register = u2f
+            .register_request(application, fake_challenge())
+            .await
+            .unwrap();
+        assert!(matches!(register, Response::UnknownError));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn requests_without_approval_time_out() {
+        let application = AppId([1u8; 32]);
+        let secrets = InMemorySecretStore::new();
+        let crypto = OpenSSLCryptoOperations::new(get_test_attestation());
+        let application_key = crypto.generate_application_key(&application).unwrap();
+        secrets.add_application_key(&application_key).unwrap();
+        let presence = NeverApprovingUserPresence::default();
+        let withdrawn_prompts = Arc::clone(&presence.withdrawn_prompts);
+        let u2f = U2f::new(secrets, crypto, presence);
+
+        let start = tokio::time::Instant::now();
+        let register = u2f
+            .register_request(application, fake_challenge())
+            .await
+            .unwrap();
+        assert!(matches!(register, Response::TestOfUserPresenceNotSatisfied));
+        assert_eq!(start.elapsed(), Config::default().approval_timeout);
+        assert_eq!(withdrawn_prompts.load(Ordering::SeqCst), 1);
+
+        let authenticate = u2f
+            .authenticate_request(
+                AuthenticateControlCode::EnforceUserPresenceAndSign,
+                fake_challenge(),
+                application,
+                application_key.handle,
+            )
+            .await
+            .unwrap();
+        assert!(matches!(
+            authenticate,
+            Response::TestOfUserPresenceNotSatisfied
+        ));
+        assert_eq!(withdrawn_prompts.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn wink_with_slow_user_presence_times_out() {
+        let secrets = InMemorySecretStore::new();
+        let crypto = OpenSSLCryptoOperations::new(get_test_attestation());
+        let presence = SlowWinkUserPresence;
+        let u2f = U2f::new(secrets, crypto, presence);
+
+        let start = tokio::time::Instant::now();
+        let response = u2f.wink_request().await.unwrap();
+
+        assert!(matches!(response, Response::DidWink));
+        assert_eq!(start.elapsed(), Config::default().wink_timeout);
+    }
+
     #[tokio::test]
     async fn register_with_rejected_approval_errors() {
         let secrets = InMemorySecretStore::new();
@@ -822,6 +1455,7 @@ AwEHoUQDQgAEryDZdIOGjRKLLyG6Mkc4oSVUDBndagZDDbdwLcUdNLzFlHx/yqYl
                 application.clone(),
                 authentication_challenge.clone(),
                 registration.key_handle.clone(),
+                true,
             )
             .await
             .unwrap();
@@ -871,6 +1505,19 @@ AwEHoUQDQgAEryDZdIOGjRKLLyG6Mkc4oSVUDBndagZDDbdwLcUdNLzFlHx/yqYl
         );
     }
 
+    #[test]
+    fn message_to_sign_for_authenticate_layout() {
+        let application = AppId([0x11; 32]);
+        let challenge = Challenge([0x22; 32]);
+
+        let message = message_to_sign_for_authenticate(&application, &challenge, 0x01, 0x01020304);
+
+        let mut expected = vec![0x11; 32];
+        expected.extend_from_slice(&[0x01, 0x01, 0x02, 0x03, 0x04]);
+        expected.extend_from_slice(&[0x22; 32]);
+        assert_eq!(message, expected);
+    }
+
     fn verify_signature<T>(signature: &dyn Signature, data: &[u8], public_key: &PKeyRef<T>)
     where
         T: HasPublic,
@@ -1,8 +1,10 @@
 use std::io;
+use std::sync::Mutex;
 
 use crate::app_id::AppId;
 use crate::application_key::ApplicationKey;
 use crate::attestation::{Attestation, AttestationCertificate};
+use crate::constants::{DEFAULT_KEY_HANDLE_LEN, MAX_KEY_HANDLE_LEN};
 use crate::key_handle::KeyHandle;
 use crate::private_key::PrivateKey;
 use openssl::ec::{EcGroup, EcKey};
@@ -10,6 +12,9 @@ use openssl::hash::MessageDigest;
 use openssl::nid::Nid;
 use openssl::pkey::PKey;
 use openssl::sign::Signer;
+use rand::rngs::OsRng;
+use rand::{CryptoRng, RngCore};
+use tracing::warn;
 
 use super::CryptoOperations;
 use super::SignError;
@@ -17,21 +22,62 @@ use super::Signature;
 
 pub struct OpenSSLCryptoOperations {
     attestation: Attestation,
+    rng: Mutex<Box<dyn RngCore + Send>>,
+    key_handle_len: usize,
 }
 
 impl OpenSSLCryptoOperations {
     pub fn new(attestation: Attestation) -> OpenSSLCryptoOperations {
-        OpenSSLCryptoOperations { attestation }
+        OpenSSLCryptoOperations::with_rng(attestation, OsRng)
     }
 
-    fn generate_key() -> PrivateKey {
-        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
-        let ec_key = EcKey::generate(&group).unwrap();
-        PrivateKey(ec_key)
+    /// Draw key handles from the given random number generator instead of the operating system's.
+    /// Key handles must be unpredictable, so only cryptographically secure generators are accepted.
+    pub fn with_rng<R>(attestation: Attestation, rng: R) -> OpenSSLCryptoOperations
+    where
+        R: RngCore + CryptoRng + Send + 'static,
+    {
+        OpenSSLCryptoOperations {
+            attestation,
+            rng: Mutex::new(Box::new(rng)),
+            key_handle_len: DEFAULT_KEY_HANDLE_LEN,
+        }
     }
 
-    fn generate_key_handle() -> io::Result<KeyHandle> {
-        Ok(rand::random())
+    /// Generate key handles of the given length in bytes, instead of the default of 64.
+    ///
+    /// Longer key handles make collisions between registrations less likely, at the cost
+    /// of larger registration responses. U2F limits key handles to at most 255 bytes.
+    pub fn with_key_handle_len(mut self, len: usize) -> io::Result<OpenSSLCryptoOperations> {
+        if len == 0 || len > MAX_KEY_HANDLE_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Key handle length must be between 1 and {} bytes, got {}",
+                    MAX_KEY_HANDLE_LEN, len
+                ),
+            ));
+        }
+        self.key_handle_len = len;
+        Ok(self)
+    }
+
+    fn generate_key() -> io::Result<PrivateKey> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+        let ec_key = EcKey::generate(&group)?;
+        Ok(PrivateKey(ec_key))
+    }
+
+    fn generate_key_handle(&self) -> io::Result<KeyHandle> {
+        let mut bytes = vec![0u8; self.key_handle_len];
+        let mut rng = self.rng.lock().unwrap_or_else(|poisoned| {
+            // A panicking generator leaves no partial key handle behind, the next fill starts over
+            warn!("Random number generator lock was poisoned by a panic, recovering");
+            poisoned.into_inner()
+        });
+        rng.try_fill_bytes(&mut bytes)
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        Ok(KeyHandle::from(&bytes))
     }
 }
 
@@ -41,8 +87,8 @@ impl CryptoOperations for OpenSSLCryptoOperations {
     }
 
     fn generate_application_key(&self, application: &AppId) -> io::Result<ApplicationKey> {
-        let key = Self::generate_key();
-        let handle = Self::generate_key_handle()?;
+        let key = Self::generate_key()?;
+        let handle = self.generate_key_handle()?;
         Ok(ApplicationKey::new(*application, handle, key))
     }
 
@@ -52,10 +98,10 @@ impl CryptoOperations for OpenSSLCryptoOperations {
 
     fn sign(&self, key: &PrivateKey, data: &[u8]) -> Result<Box<dyn Signature>, SignError> {
         let ec_key = key.0.to_owned();
-        let pkey = PKey::from_ec_key(ec_key).unwrap();
-        let mut signer = Signer::new(MessageDigest::sha256(), &pkey).unwrap();
-        signer.update(data).unwrap();
-        let signature = signer.sign_to_vec().unwrap();
+        let pkey = PKey::from_ec_key(ec_key)?;
+        let mut signer = Signer::new(MessageDigest::sha256(), &pkey)?;
+        signer.update(data)?;
+        let signature = signer.sign_to_vec()?;
         Ok(Box::new(RawSignature(signature)))
     }
 }
@@ -70,3 +116,130 @@ impl AsRef<[u8]> for RawSignature {
         self.0.as_ref()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::panic::{self, AssertUnwindSafe};
+
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::self_signed_attestation;
+
+    #[test]
+    fn generate_application_key_uses_configured_key_handle_len() {
+        let crypto = OpenSSLCryptoOperations::new(self_signed_attestation())
+            .with_key_handle_len(32)
+            .unwrap();
+        let application = AppId([0u8; 32]);
+
+        let handles: HashSet<Vec<u8>> = (0..100)
+            .map(|_| {
+                let key = crypto.generate_application_key(&application).unwrap();
+                key.handle.as_ref().to_vec()
+            })
+            .collect();
+
+        assert_eq!(handles.len(), 100);
+        assert!(handles.iter().all(|handle| handle.len() == 32));
+    }
+
+    #[test]
+    fn generate_application_key_draws_key_handle_from_rng() {
+        let application = AppId([0u8; 32]);
+        let generate_handles = |seed: u64| -> Vec<Vec<u8>> {
+            let crypto = OpenSSLCryptoOperations::with_rng(
+                self_signed_attestation(),
+                StdRng::seed_from_u64(seed),
+            );
+            (0..3)
+                .map(|_| {
+                    let key = crypto.generate_application_key(&application).unwrap();
+                    key.handle.as_ref().to_vec()
+                })
+                .collect()
+        };
+
+        let mut expected = vec![0u8; DEFAULT_KEY_HANDLE_LEN];
+        StdRng::seed_from_u64(7).fill_bytes(&mut expected);
+
+        assert_eq!(generate_handles(7), generate_handles(7));
+        assert_eq!(generate_handles(7)[0], expected);
+        assert_ne!(generate_handles(7), generate_handles(8));
+    }
+
+    /// Panics on its first use, then fills with zeros.
+    struct PanicsOnceRng {
+        panicked: bool,
+    }
+
+    impl RngCore for PanicsOnceRng {
+        fn next_u32(&mut self) -> u32 {
+            panic!("PanicsOnceRng only supports try_fill_bytes")
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            panic!("PanicsOnceRng only supports try_fill_bytes")
+        }
+
+        fn fill_bytes(&mut self, _dest: &mut [u8]) {
+            panic!("PanicsOnceRng only supports try_fill_bytes")
+        }
+
+        fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+            if !self.panicked {
+                self.panicked = true;
+                panic!("PanicsOnceRng panics on first use");
+            }
+            dest.fill(0);
+            Ok(())
+        }
+    }
+
+    impl CryptoRng for PanicsOnceRng {}
+
+    #[test]
+    fn generate_application_key_after_rng_panic() {
+        let crypto = OpenSSLCryptoOperations::with_rng(
+            self_signed_attestation(),
+            PanicsOnceRng { panicked: false },
+        );
+        let application = AppId([0u8; 32]);
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            crypto.generate_application_key(&application)
+        }));
+        assert!(result.is_err());
+        assert!(crypto.rng.is_poisoned());
+
+        let key = crypto.generate_application_key(&application).unwrap();
+
+        assert_eq!(key.handle.as_ref(), &[0u8; DEFAULT_KEY_HANDLE_LEN][..]);
+    }
+
+    #[test]
+    fn sign_returns_openssl_error_when_signing_fails() {
+        let crypto = OpenSSLCryptoOperations::new(self_signed_attestation());
+        // OpenSSL only signs SM2 keys with the SM3 digest, so signing with SHA-256 fails
+        let group = EcGroup::from_curve_name(Nid::SM2).unwrap();
+        let key = PrivateKey(EcKey::generate(&group).unwrap());
+
+        let result = crypto.sign(&key, b"message");
+
+        assert!(matches!(result, Err(SignError::OpenSsl(_))));
+    }
+
+    #[test]
+    fn with_key_handle_len_rejects_invalid_len() {
+        let attestation = self_signed_attestation();
+
+        assert!(OpenSSLCryptoOperations::new(attestation.clone())
+            .with_key_handle_len(0)
+            .is_err());
+        assert!(OpenSSLCryptoOperations::new(attestation)
+            .with_key_handle_len(MAX_KEY_HANDLE_LEN + 1)
+            .is_err());
+    }
+}
@@ -1,7 +1,10 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
 use openssl::bn::BigNumContext;
 use openssl::ec::{EcGroup, EcKey, PointConversionForm};
 use openssl::nid::Nid;
 use openssl::pkey::Public;
+use serde_json::json;
 
 use crate::private_key::PrivateKey;
 
@@ -21,16 +24,19 @@ impl PublicKey {
         use crate::constants::EC_POINT_FORMAT_UNCOMPRESSED;
         use openssl::ec::EcPoint;
 
-        let mut ctx = BigNumContext::new().unwrap();
         if bytes.len() != 65 {
             return Err(format!("Expected 65 bytes, found {}", bytes.len()));
         }
         if bytes[0] != EC_POINT_FORMAT_UNCOMPRESSED {
             return Err(String::from("Expected uncompressed point"));
         }
-        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
-        let point = EcPoint::from_bytes(&group, bytes, &mut ctx).unwrap();
-        Ok(PublicKey(EcKey::from_public_key(&group, &point).unwrap()))
+        let mut ctx = BigNumContext::new().map_err(|err| err.to_string())?;
+        let group =
+            EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).map_err(|err| err.to_string())?;
+        let point = EcPoint::from_bytes(&group, bytes, &mut ctx)
+            .map_err(|_| String::from("Expected point on the P-256 curve"))?;
+        let key = EcKey::from_public_key(&group, &point).map_err(|err| err.to_string())?;
+        Ok(PublicKey(key))
     }
 
     /// Raw ANSI X9.62 formatted Elliptic Curve public key [SEC1].
@@ -44,6 +50,19 @@ impl PublicKey {
             .to_bytes(self.0.group(), form, &mut ctx)
             .unwrap()
     }
+
+    /// JSON Web Key [RFC 7517] representation of the public key, with the
+    /// curve point coordinates base64url encoded as described in [RFC 7518].
+    pub fn to_jwk(&self) -> serde_json::Value {
+        let raw = self.to_raw();
+        let (x, y) = raw[1..].split_at(32);
+        json!({
+            "kty": "EC",
+            "crv": "P-256",
+            "x": URL_SAFE_NO_PAD.encode(x),
+            "y": URL_SAFE_NO_PAD.encode(y),
+        })
+    }
 }
 
 impl From<PublicKey> for EcKey<Public> {
@@ -51,3 +70,53 @@ impl From<PublicKey> for EcKey<Public> {
         key.0
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app_id::AppId;
+    use crate::application_key::ApplicationKey;
+    use crate::constants::EC_POINT_FORMAT_UNCOMPRESSED;
+    use crate::openssl_crypto::OpenSSLCryptoOperations;
+    use crate::self_signed_attestation::self_signed_attestation;
+    use crate::CryptoOperations;
+
+    fn generate_application_key() -> ApplicationKey {
+        OpenSSLCryptoOperations::new(self_signed_attestation())
+            .generate_application_key(&AppId([0u8; 32]))
+            .unwrap()
+    }
+
+    #[test]
+    fn to_jwk_coordinates_match_raw_key() {
+        let public_key = generate_application_key().public_key();
+        let raw = public_key.to_raw();
+
+        let jwk = public_key.to_jwk();
+        let x = URL_SAFE_NO_PAD.decode(jwk["x"].as_str().unwrap()).unwrap();
+        let y = URL_SAFE_NO_PAD.decode(jwk["y"].as_str().unwrap()).unwrap();
+
+        assert_eq!(jwk["kty"], "EC");
+        assert_eq!(jwk["crv"], "P-256");
+        assert_eq!(raw[0], EC_POINT_FORMAT_UNCOMPRESSED);
+        assert_eq!(x, &raw[1..33]);
+        assert_eq!(y, &raw[33..65]);
+    }
+
+    #[test]
+    fn from_bytes_round_trips_raw_key() {
+        let raw = generate_application_key().public_key().to_raw();
+
+        let public_key = PublicKey::from_bytes(&raw).unwrap();
+
+        assert_eq!(public_key.to_raw(), raw);
+    }
+
+    #[test]
+    fn from_bytes_rejects_point_off_curve() {
+        let mut raw = vec![EC_POINT_FORMAT_UNCOMPRESSED];
+        raw.extend_from_slice(&[1u8; 64]);
+
+        assert!(PublicKey::from_bytes(&raw).is_err());
+    }
+}
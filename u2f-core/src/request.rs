@@ -1,3 +1,4 @@
+use std::io;
 use std::io::Cursor;
 use std::io::Read;
 use std::result::Result;
@@ -6,8 +7,10 @@ use crate::app_id::AppId;
 use crate::constants::*;
 use crate::key_handle::KeyHandle;
 use byteorder::{BigEndian, ReadBytesExt};
+use thiserror::Error;
 
 use super::Challenge;
+use super::StatusCode;
 
 #[derive(Debug)]
 pub enum AuthenticateControlCode {
@@ -32,27 +35,62 @@ pub enum Request {
     Wink,
 }
 
+#[derive(Debug, Error)]
+pub enum RequestDecodeError {
+    #[error("Unsupported instruction: {0:#04x}")]
+    UnsupportedInstruction(u8),
+
+    #[error("Invalid request length")]
+    InvalidLength,
+
+    #[error("Invalid request parameters")]
+    WrongData,
+}
+
+/// Requests are decoded from an in-memory buffer, so the only way reading can fail is running
+/// out of bytes before the end of the request.
+impl From<io::Error> for RequestDecodeError {
+    fn from(_: io::Error) -> Self {
+        RequestDecodeError::InvalidLength
+    }
+}
+
+impl RequestDecodeError {
+    /// Status word to respond with, so the client can tell why its request was rejected.
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            RequestDecodeError::UnsupportedInstruction(_) => {
+                StatusCode::RequestInstructionNotSuppored
+            }
+            RequestDecodeError::InvalidLength => StatusCode::RequestLengthInvalid,
+            // Encoded as SW_WRONG_DATA, the same status word used for invalid key handles
+            RequestDecodeError::WrongData => StatusCode::InvalidKeyHandle,
+        }
+    }
+}
+
 impl Request {
     /// Only supports Extended Length Encoding
-    pub fn decode(data: &[u8]) -> Result<Request, ()> {
+    pub fn decode(data: &[u8]) -> Result<Request, RequestDecodeError> {
         let mut reader = Cursor::new(data);
 
         // CLA: Reserved to be used by the underlying transport protocol
-        let _class_byte = reader.read_u8().unwrap();
+        let _class_byte = reader.read_u8()?;
         // TODO check or error with RequestClassNotSupported
 
         // INS: U2F command code
-        let command_code = reader.read_u8().unwrap();
-        // TODO check or error with RequestInstructionNotSuppored
+        let command_code = reader.read_u8()?;
 
         // P1, P2: Parameter 1 and 2, defined by each command.
-        let parameter1 = reader.read_u8().unwrap();
-        let parameter2 = reader.read_u8().unwrap();
+        let parameter1 = reader.read_u8()?;
+        let parameter2 = reader.read_u8()?;
 
         // Extended Length Encoding
         // Always begins with a byte of value 0
-        let zero_byte = reader.read_u8().unwrap();
-        assert_eq!(zero_byte, 0);
+        let zero_byte = reader.read_u8()?;
+        if zero_byte != 0 {
+            return Err(RequestDecodeError::InvalidLength);
+        }
 
         // Nc: Length of the request-data, range 0..65 535
         // Lc: Encoding of Nc as two bytes
@@ -65,13 +103,13 @@ impl Request {
             }
             _ => {
                 // Lc in big-endian order
-                reader.read_u16::<BigEndian>().unwrap() as usize
+                reader.read_u16::<BigEndian>()? as usize
             }
         };
 
         // Request-data
         let mut request_data = vec![0u8; request_data_len];
-        reader.read_exact(&mut request_data[..]).unwrap();
+        reader.read_exact(&mut request_data[..])?;
 
         // Ne: Maximum length of the response data, range 0..65 536
         // Le: Encoding of Ne as two bytes
@@ -84,7 +122,7 @@ impl Request {
             }
             2 => {
                 // Encoded as: Le1 Le2
-                let mut value = reader.read_u16::<BigEndian>().unwrap() as usize;
+                let mut value = reader.read_u16::<BigEndian>()? as usize;
                 // When Ne = 65 536, let Le1 = 0 and Le2 = 0.
                 if value == 0 {
                     // The MSB is lost when encoding to two bytes, but
@@ -94,7 +132,7 @@ impl Request {
                 }
                 value
             }
-            _ => return Err(()),
+            _ => return Err(RequestDecodeError::InvalidLength),
         };
 
         // TODO If the instruction is not expected to yield any response bytes, L e may be omitted. O
@@ -103,43 +141,47 @@ impl Request {
             REGISTER_COMMAND_CODE => {
                 // The challenge parameter [32 bytes].
                 let mut challenge_parameter = [0u8; 32];
-                reader.read_exact(&mut challenge_parameter[..]).unwrap();
+                reader.read_exact(&mut challenge_parameter[..])?;
 
                 // The application parameter [32 bytes].
                 let mut application_parameter = [0u8; 32];
-                reader.read_exact(&mut application_parameter[..]).unwrap();
+                reader.read_exact(&mut application_parameter[..])?;
 
-                assert_eq!(reader.position() as usize, request_data_len);
+                if reader.position() as usize != request_data_len {
+                    return Err(RequestDecodeError::InvalidLength);
+                }
                 Request::Register {
                     application: AppId(application_parameter),
                     challenge: Challenge(challenge_parameter),
                 }
             }
             AUTHENTICATE_COMMAND_CODE => {
-                assert_eq!(parameter2, 0);
+                if parameter2 != 0 {
+                    return Err(RequestDecodeError::WrongData);
+                }
 
                 // Control byte (P1).
                 let control_code = match parameter1 {
                     AUTH_CHECK_ONLY => AuthenticateControlCode::CheckOnly,
                     AUTH_ENFORCE => AuthenticateControlCode::EnforceUserPresenceAndSign,
                     AUTH_DONT_ENFORCE => AuthenticateControlCode::DontEnforceUserPresenceAndSign,
-                    _ => panic!("Unknown control code"),
+                    _ => return Err(RequestDecodeError::WrongData),
                 };
 
                 // The challenge parameter [32 bytes].
                 let mut challenge_parameter = [0u8; 32];
-                reader.read_exact(&mut challenge_parameter[..]).unwrap();
+                reader.read_exact(&mut challenge_parameter[..])?;
 
                 // The application parameter [32 bytes].
                 let mut application_parameter = [0u8; 32];
-                reader.read_exact(&mut application_parameter[..]).unwrap();
+                reader.read_exact(&mut application_parameter[..])?;
 
                 // key handle length byte [1 byte]
-                let key_handle_len = reader.read_u8().unwrap();
+                let key_handle_len = reader.read_u8()?;
 
                 // key handle [length specified in previous field]
                 let mut key_handle_bytes = vec![0u8; key_handle_len as usize];
-                reader.read_exact(&mut key_handle_bytes[..]).unwrap();
+                reader.read_exact(&mut key_handle_bytes[..])?;
 
                 Request::Authenticate {
                     application: AppId(application_parameter),
@@ -149,13 +191,121 @@ impl Request {
                 }
             }
             VERSION_COMMAND_CODE => {
-                assert_eq!(parameter1, 0);
-                assert_eq!(parameter2, 0);
-                assert_eq!(request_data_len, 0);
+                if parameter1 != 0 || parameter2 != 0 {
+                    return Err(RequestDecodeError::WrongData);
+                }
+                if request_data_len != 0 {
+                    return Err(RequestDecodeError::InvalidLength);
+                }
                 Request::GetVersion
             }
-            _ => panic!("Command not implemented"),
+            _ => return Err(RequestDecodeError::UnsupportedInstruction(command_code)),
         };
         Ok(request)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status_word(error: &RequestDecodeError) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        error.status_code().write(&mut bytes);
+        bytes
+    }
+
+    #[test]
+    fn decode_version() {
+        let request = Request::decode(&[0x00, VERSION_COMMAND_CODE, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        assert_matches!(request, Ok(Request::GetVersion));
+    }
+
+    #[test]
+    fn decode_unknown_instruction_errors() {
+        let error = Request::decode(&[0x00, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00]).unwrap_err();
+        assert_matches!(error, RequestDecodeError::UnsupportedInstruction(0x40));
+        assert_eq!(status_word(&error), vec![0x6D, 0x00]);
+    }
+
+    #[test]
+    fn decode_trailing_bytes_errors() {
+        let error = Request::decode(&[
+            0x00,
+            VERSION_COMMAND_CODE,
+            0x00,
+            0x00,
+            0x00,
+            0x00,
+            0x00,
+            0x00,
+        ])
+        .unwrap_err();
+        assert_matches!(error, RequestDecodeError::InvalidLength);
+        assert_eq!(status_word(&error), vec![0x67, 0x00]);
+    }
+
+    fn authenticate_apdu(control_code: u8, request_data: &[u8]) -> Vec<u8> {
+        let mut apdu = vec![0x00, AUTHENTICATE_COMMAND_CODE, control_code, 0x00, 0x00];
+        apdu.extend_from_slice(&(request_data.len() as u16).to_be_bytes());
+        apdu.extend_from_slice(request_data);
+        apdu
+    }
+
+    fn authenticate_request_data() -> Vec<u8> {
+        let mut request_data = vec![0u8; 64];
+        request_data.push(4);
+        request_data.extend_from_slice(&[1, 2, 3, 4]);
+        request_data
+    }
+
+    #[test]
+    fn decode_authenticate() {
+        let apdu = authenticate_apdu(AUTH_ENFORCE, &authenticate_request_data());
+        let request = Request::decode(&apdu);
+        assert_matches!(
+            request,
+            Ok(Request::Authenticate {
+                control_code: AuthenticateControlCode::EnforceUserPresenceAndSign,
+                ..
+            })
+        );
+    }
+
+    #[test]
+    fn decode_truncated_header_errors() {
+        let error = Request::decode(&[0x00, VERSION_COMMAND_CODE, 0x00, 0x00]).unwrap_err();
+        assert_matches!(error, RequestDecodeError::InvalidLength);
+    }
+
+    #[test]
+    fn decode_truncated_request_data_errors() {
+        let mut apdu = authenticate_apdu(AUTH_ENFORCE, &authenticate_request_data());
+        apdu.truncate(apdu.len() - 2);
+        let error = Request::decode(&apdu).unwrap_err();
+        assert_matches!(error, RequestDecodeError::InvalidLength);
+    }
+
+    #[test]
+    fn decode_truncated_key_handle_errors() {
+        let mut request_data = authenticate_request_data();
+        request_data.truncate(request_data.len() - 2);
+        let error = Request::decode(&authenticate_apdu(AUTH_ENFORCE, &request_data)).unwrap_err();
+        assert_matches!(error, RequestDecodeError::InvalidLength);
+    }
+
+    #[test]
+    fn decode_unknown_control_code_errors() {
+        let apdu = authenticate_apdu(0x42, &authenticate_request_data());
+        let error = Request::decode(&apdu).unwrap_err();
+        assert_matches!(error, RequestDecodeError::WrongData);
+        assert_eq!(status_word(&error), vec![0x6A, 0x80]);
+    }
+
+    #[test]
+    fn decode_version_with_parameters_errors() {
+        let error = Request::decode(&[0x00, VERSION_COMMAND_CODE, 0x01, 0x00, 0x00, 0x00, 0x00])
+            .unwrap_err();
+        assert_matches!(error, RequestDecodeError::WrongData);
+    }
+}
@@ -149,3 +149,49 @@ impl Into<io::Error> for ResponseError {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct FakeSignature(Vec<u8>);
+
+    impl Signature for FakeSignature {}
+
+    impl AsRef<[u8]> for FakeSignature {
+        fn as_ref(&self) -> &[u8] {
+            &self.0
+        }
+    }
+
+    fn authentication_bytes(counter: Counter) -> Vec<u8> {
+        Response::Authentication {
+            counter,
+            signature: Box::new(FakeSignature(vec![0xAA; 4])),
+            user_present: true,
+        }
+        .into_bytes()
+    }
+
+    #[test]
+    fn authentication_encodes_zero_counter_big_endian() {
+        let bytes = authentication_bytes(0);
+        assert_eq!(&bytes[1..5], &[0x00, 0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn authentication_encodes_max_counter_big_endian() {
+        let bytes = authentication_bytes(u32::MAX);
+        assert_eq!(&bytes[1..5], &[0xFF, 0xFF, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn authentication_layout() {
+        let bytes = authentication_bytes(0x01020304);
+        assert_eq!(
+            bytes,
+            vec![0x01, 0x01, 0x02, 0x03, 0x04, 0xAA, 0xAA, 0xAA, 0xAA, 0x90, 0x00]
+        );
+    }
+}
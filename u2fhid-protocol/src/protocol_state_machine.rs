@@ -471,9 +471,16 @@ where
         let channel_id = request.channel_id;
         match request.message {
             RequestMessage::EncapsulatedRequest { data } => {
-                // TODO no unwrap
                 debug!(len = data.len(), "RequestMessage::EncapsulatedRequest");
-                self.dispatch(u2f_core::Request::decode(&data).unwrap())
+                match u2f_core::Request::decode(&data) {
+                    Ok(request) => self.dispatch(request),
+                    Err(error) => {
+                        info!(?error, "Unable to decode U2F request");
+                        let mut data = Vec::new();
+                        error.status_code().write(&mut data);
+                        Box::pin(future::ok(ResponseMessage::EncapsulatedResponse { data }))
+                    }
+                }
             }
             RequestMessage::Init { nonce } => {
                 // TODO Check what channnel message came in on